@@ -1,91 +1,1964 @@
 use core;
+use core::cell::{Cell, RefCell};
 use core::fmt::Debug;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 use gcode::parser::Line;
 use gcode::{Tokenizer, Parser};
 use utils::Point3;
-use motion::CartesianMotionPlanner;
+use motion::{CartesianMotionPlanner, ReplayHandle};
+use hardware::{HardwareGpio, HardwareTime, Serial};
+#[cfg(feature = "net")]
+use smoltcp::socket::{SocketHandle, SocketSet, TcpSocket};
+
+/// How many hardware ticks the motion task waits between two checks of the
+/// command queue, whether or not a command is queued. Real step timing is
+/// computed by the `motion` crate; this is just the task's poll interval.
+const STEP_INTERVAL_TICKS: u32 = 1;
+
+/// How many distinct repeated move sequences (infill passes, skirts,
+/// calibration grids, ...) can have a replay recording cached at once.
+const REPLAY_SLOTS: usize = 4;
 
 static LINE_ENDING: u8 = '\n' as u8;
 
-struct Printer {
-
-}
-
-// struct Printer<'a, H: 'a> {
-//     /// Buffer for Gcodes that must be executed in order
-//     /// Moves, temperatures, fans, etc
-//     gcode_buffer: [Option<Line>; 32],
-//     gcode_buffer_head: u8,
-//     gcode_buffer_tail: u8,
-
-//     /// Buffer for Gcodes that should be executed as fast as possible
-//     /// E-stop, informations, etc
-//     immediate_gcode_buffer: [Option<Line>; 32],
-//     immediate_gcode_buffer_head: u8,
-//     immediate_gcode_buffer_tail: u8,
-
-//     serial: Serial,
-
-//     serial_buffer: [u8; 256],
-//     serial_bytes: u8,
-
-//     motion: CartesianMotionPlanner<'a, H>,
-
-//     hardware: &'a H,
-// }
-
-// impl<'a, H: HardwareGpio + HardwareTime + Debug> Printer<'a, H> {
-//     fn new(hardware: &H, motion: CartesianMotionPlanner<'a, &H>) -> Printer<'a, H> {
-//         Printer {
-//             gcode_buffer: [None; 32],
-//             gcode_buffer_head: 0,
-//             gcode_buffer_tail: 0,
-//             immediate_gcode_buffer: [None; 32],
-//             immediate_gcode_buffer_head: 0,
-//             immediate_gcode_buffer_tail: 0,
-//             serial: Serial {},
-//             serial_buffer: [0; 256],
-//             serial_bytes: 0,
-//             motion: motion,
-//             hardware: hardware,
-//         }
-//     }
-
-//     fn recive_serial(&mut self) {
-//         if let Ok(byte) = self.serial.try_read_byte() {
-//             if byte != LINE_ENDING {
-//                 if self.serial_bytes < 256 {
-//                     self.serial_bytes += 1;
-//                     self.serial_buffer[self.serial_bytes] = byte;
-//                 }
-//             } else {
-//                 match core::str::from_utf8(&self.serial_buffer[0..self.serial_bytes]) {
-//                     Ok(chars) => {
-//                         let lexer = Tokenizer::new(chars.chars());
-//                         let tokens = lexer.filter_map(|t| t.ok());
-//                         let parser = Parser::new(tokens);
-//                         for line in parser {
-//                             match line {
-//                                 Ok(line) => {
-//                                     println!("Recived: {:?}", line);
-//                                     self.gcode_buffer_head += 1;
-//                                     self.gcode_buffer[self.gcode_buffer_head] = Some(line);
-//                                 }
-//                                 Err(err) => {},
-//                             }
-//                         }
-//                     }
-//                     Err(err) => {},
-//                 }
-//                 self.serial_bytes = 0;
-//             }
-//         }
-//     }
-
-//     fn update(&mut self) {
-//         self.recive_serial();
-//     }
-
-
-//     fn move_to(&self, x: Option<f32>, y: Option<f32>, z: Option<f32>) {}
-// }
+/// Sent back over the line's transport (serial or network) once a command
+/// has been validated and queued.
+static OK_RESPONSE: &'static [u8] = b"ok\n";
+
+/// Byte source/sink shared by the serial and network command paths, so
+/// `LineReceiver` doesn't need to know which one it's reading from.
+trait Transport {
+    fn try_read_byte(&mut self) -> Result<u8, ()>;
+    fn write(&mut self, bytes: &[u8]);
+}
+
+impl Transport for Serial {
+    fn try_read_byte(&mut self) -> Result<u8, ()> {
+        self.try_read_byte().map_err(|_| ())
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.write(bytes)
+    }
+}
+
+/// Wraps a single accepted TCP client so it can feed the same
+/// `LineReceiver` pipeline as `Serial`. Borrowed transiently each tick
+/// since the `SocketSet` is owned by the network interface's poll loop,
+/// not by `Printer`.
+#[cfg(feature = "net")]
+struct NetTransport<'a, 'b: 'a> {
+    sockets: &'a mut SocketSet<'b>,
+    handle: SocketHandle,
+}
+
+#[cfg(feature = "net")]
+impl<'a, 'b> Transport for NetTransport<'a, 'b> {
+    fn try_read_byte(&mut self) -> Result<u8, ()> {
+        let mut socket = self.sockets.get::<TcpSocket>(self.handle);
+        if !socket.can_recv() {
+            return Err(());
+        }
+        let mut byte = [0u8; 1];
+        match socket.recv_slice(&mut byte) {
+            Ok(1) => Ok(byte[0]),
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let mut socket = self.sockets.get::<TcpSocket>(self.handle);
+        let _ = socket.send_slice(bytes);
+    }
+}
+
+/// Accumulates bytes from a transport into whole lines, applies the
+/// checksum/sequencing protocol, and feeds parsed commands into a
+/// `GcodeQueue`. Shared by the serial and network command paths so both
+/// get the same line framing, checksums, and `ok`/`Resend` responses.
+struct LineReceiver {
+    buffer: [u8; 256],
+    bytes: usize,
+
+    /// Line number of the last accepted checksummed command. The next
+    /// checksummed line must carry `last_line + 1` or it is rejected with
+    /// a `Resend` request, mirroring the Marlin line-numbering protocol.
+    last_line: u32,
+}
+
+impl LineReceiver {
+    fn new() -> LineReceiver {
+        LineReceiver {
+            buffer: [0; 256],
+            bytes: 0,
+            last_line: 0,
+        }
+    }
+
+    /// Drains every byte `transport` currently has ready, accumulating
+    /// whole lines and dispatching each one into `queue`.
+    fn poll<T: Transport>(&mut self, transport: &mut T, queue: &mut GcodeQueue<Line>) {
+        while let Ok(byte) = transport.try_read_byte() {
+            if byte != LINE_ENDING {
+                if self.bytes < self.buffer.len() {
+                    self.buffer[self.bytes] = byte;
+                    self.bytes += 1;
+                }
+            } else {
+                self.handle_line(transport, queue);
+                self.bytes = 0;
+            }
+        }
+    }
+
+    /// Checks the accumulated line against the Marlin-style `Nxxxx ... *cc`
+    /// framing, then tokenizes and queues whatever payload remains.
+    ///
+    /// Lines with no `N`/`*` framing (e.g. manual console input) are
+    /// accepted unconditionally. Checksummed lines must match both the XOR
+    /// checksum and the expected next line number, or the line is dropped
+    /// and a `Resend` is sent back so the host retransmits.
+    fn handle_line<T: Transport>(&mut self, transport: &mut T, queue: &mut GcodeQueue<Line>) {
+        let raw = &self.buffer[0..self.bytes];
+        let (payload, accepted_line) = match strip_checksum(raw, self.last_line) {
+            Ok(Checked::Framed(payload, line_number)) => (payload, Some(line_number)),
+            Ok(Checked::Unframed(payload)) => (payload, None),
+            Err(expected) => {
+                send_resend(transport, expected);
+                return;
+            }
+        };
+
+        if queue.is_full() {
+            // Drop the line without responding, so the host times out
+            // waiting for `ok` and retransmits it. A retransmit is accepted
+            // only after a slot has been popped; `MotionTask` does not pop,
+            // so a full queue stays full.
+            return;
+        }
+
+        match core::str::from_utf8(payload) {
+            Ok(chars) => {
+                let lexer = Tokenizer::new(chars.chars());
+                let tokens = lexer.filter_map(|t| t.ok());
+                let parser = Parser::new(tokens);
+                let mut queued_all = true;
+                let mut pushed = 0usize;
+                for line in parser {
+                    match line {
+                        Ok(line) => {
+                            if queue.push(line).is_err() {
+                                queued_all = false;
+                                break;
+                            }
+                            pushed += 1;
+                        }
+                        Err(_err) => {}
+                    }
+                }
+
+                if !queued_all {
+                    // Some of this line's commands never made it into the
+                    // queue. Roll back the ones that did, so they don't
+                    // execute twice when the host resends the whole line
+                    // after we don't ack or advance `last_line` below - they
+                    // were never acked, so the host has no reason to believe
+                    // they're queued.
+                    for _ in 0..pushed {
+                        queue.unpush();
+                    }
+                    return;
+                }
+
+                if let Some(line_number) = accepted_line {
+                    self.last_line = line_number;
+                }
+                transport.write(OK_RESPONSE);
+            }
+            Err(_err) => {}
+        }
+    }
+}
+
+fn send_resend<T: Transport>(transport: &mut T, expected_line: u32) {
+    transport.write(b"Resend: ");
+    let mut digits = [0u8; 10];
+    let len = write_u32(expected_line, &mut digits);
+    transport.write(&digits[0..len]);
+    transport.write(b"\n");
+}
+
+struct Printer<'a, H: 'a> {
+    /// Queue for Gcodes that must be executed in order
+    /// Moves, temperatures, fans, etc
+    ///
+    /// Wrapped in a `RefCell` so the reader and motion tasks can each take
+    /// a short borrow from `&Printer` during their own `poll` without
+    /// fighting the borrow checker over a field they both touch.
+    gcode_buffer: RefCell<GcodeQueue<Line>>,
+
+    /// Buffer for Gcodes that should be executed as fast as possible
+    /// E-stop, informations, etc
+    immediate_gcode_buffer: [Option<Line>; 32],
+    immediate_gcode_buffer_head: u8,
+    immediate_gcode_buffer_tail: u8,
+
+    serial: RefCell<DecompressingTransport<Serial>>,
+    serial_receiver: RefCell<LineReceiver>,
+
+    /// Accepted TCP client and its line receiver, present once a host has
+    /// connected over the network command channel.
+    #[cfg(feature = "net")]
+    net_handle: RefCell<Option<SocketHandle>>,
+    #[cfg(feature = "net")]
+    net_receiver: RefCell<LineReceiver>,
+
+    /// Gzip-detection/inflation state for the network command channel, kept
+    /// separately from a `DecompressingTransport` because `NetTransport`
+    /// only borrows its `SocketSet` for the duration of one `recive_net`
+    /// call and can't be stored across ticks the way `Serial` is. Gives the
+    /// network path the same optional gzip framing as serial instead of
+    /// always feeding the parser raw bytes.
+    #[cfg(feature = "net")]
+    net_decompress: RefCell<DecompressState>,
+
+    motion: RefCell<CartesianMotionPlanner<'a, &'a H>>,
+
+    /// Cached step-timing recordings for repeated move sequences (infill
+    /// passes, skirts, calibration grids, ...), keyed by caller-assigned
+    /// slot so a later pass can replay one directly instead of re-running
+    /// acceleration planning. `None` until `finish_recording` has captured
+    /// one into that slot.
+    replays: RefCell<[Option<ReplayHandle>; REPLAY_SLOTS]>,
+
+    hardware: &'a H,
+}
+
+impl<'a, H: HardwareGpio + HardwareTime + Debug> Printer<'a, H> {
+    fn new(hardware: &'a H, motion: CartesianMotionPlanner<'a, &'a H>) -> Printer<'a, H> {
+        Printer {
+            gcode_buffer: RefCell::new(GcodeQueue::new()),
+            immediate_gcode_buffer: [None; 32],
+            immediate_gcode_buffer_head: 0,
+            immediate_gcode_buffer_tail: 0,
+            serial: RefCell::new(DecompressingTransport::new(Serial {})),
+            serial_receiver: RefCell::new(LineReceiver::new()),
+            #[cfg(feature = "net")]
+            net_handle: RefCell::new(None),
+            #[cfg(feature = "net")]
+            net_receiver: RefCell::new(LineReceiver::new()),
+            #[cfg(feature = "net")]
+            net_decompress: RefCell::new(DecompressState::new()),
+            motion: RefCell::new(motion),
+            replays: RefCell::new([None; REPLAY_SLOTS]),
+            hardware: hardware,
+        }
+    }
+
+    /// Registers the socket a listening `TcpSocket` accepted a client on,
+    /// so the next `recive_net` call starts reading from it.
+    #[cfg(feature = "net")]
+    fn attach_net(&self, handle: SocketHandle) {
+        *self.net_handle.borrow_mut() = Some(handle);
+        *self.net_receiver.borrow_mut() = LineReceiver::new();
+        *self.net_decompress.borrow_mut() = DecompressState::new();
+    }
+
+    /// Drains whatever bytes the connected TCP client has sent, feeding the
+    /// same command pipeline as the serial reader task - including the same
+    /// optional gzip framing `serial` gets from `DecompressingTransport`. A
+    /// no-op until a client has connected via `attach_net`.
+    ///
+    /// Unlike the serial reader, this can't be parked as a static task in
+    /// `Executor`: the `SocketSet` it reads through is owned by the
+    /// `smoltcp` interface's own poll loop, not by `Printer`, so it's
+    /// still driven explicitly once per tick by the caller. That also means
+    /// `NetTransport` can't be stored inside a `DecompressingTransport`
+    /// field the way `Serial` is - it only borrows `sockets` for this call -
+    /// so `net_decompress` holds the gzip state across ticks instead and a
+    /// fresh `NetTransport` is wrapped around it each time.
+    #[cfg(feature = "net")]
+    fn recive_net(&self, sockets: &mut SocketSet) {
+        if let Some(handle) = *self.net_handle.borrow() {
+            let mut net_decompress = self.net_decompress.borrow_mut();
+            let mut transport = DecompressingTransportRef {
+                inner: NetTransport { sockets, handle },
+                state: &mut net_decompress,
+            };
+            self.net_receiver
+                .borrow_mut()
+                .poll(&mut transport, &mut self.gcode_buffer.borrow_mut());
+        }
+    }
+
+    /// Builds the set of cooperative tasks that drive this printer: a
+    /// serial reader and the motion stepper. Borrows from `&self`, so the
+    /// caller polls the returned `Executor` for as long as `self` lives
+    /// (typically for the lifetime of a `'static` printer instance).
+    fn tasks<'p>(&'p self) -> Executor<'p, 'a, H> {
+        Executor {
+            serial_reader: ReaderTask {
+                transport: &self.serial,
+                receiver: &self.serial_receiver,
+                queue: &self.gcode_buffer,
+            },
+            motion: MotionTask {
+                queue: &self.gcode_buffer,
+                motion: &self.motion,
+                hardware: self.hardware,
+                next_deadline: Cell::new(0),
+            },
+        }
+    }
+
+    /// Advances one tick: polls the cooperative tasks (serial reader and
+    /// motion stepper) and, when built with the "net" feature, drains
+    /// whatever bytes the connected TCP client has sent via `recive_net`.
+    /// Callers should call this once per main-loop iteration instead of
+    /// driving `tasks()` or `recive_net` separately.
+    #[cfg(feature = "net")]
+    fn update(&self, sockets: &mut SocketSet) {
+        self.tasks().poll_all();
+        self.recive_net(sockets);
+    }
+
+    #[cfg(not(feature = "net"))]
+    fn update(&self) {
+        self.tasks().poll_all();
+    }
+
+    fn move_to(&self, x: Option<f32>, y: Option<f32>, z: Option<f32>) {}
+
+    /// Begins recording the planner's step timing for a repeated move
+    /// sequence. Pairs with `finish_recording`, which captures the moves
+    /// planned between the two calls into a slot for later replay.
+    fn start_recording(&self) {
+        self.motion.borrow_mut().start_record();
+    }
+
+    /// Captures the sequence recorded since `start_recording` into `slot`,
+    /// replacing whatever was previously cached there. A `slot` outside
+    /// `0..REPLAY_SLOTS` is ignored rather than panicking; the recording is
+    /// simply dropped.
+    fn finish_recording(&self, slot: usize) {
+        let handle = self.motion.borrow_mut().finish_record();
+        if let Some(entry) = self.replays.borrow_mut().get_mut(slot) {
+            *entry = Some(handle);
+        }
+    }
+
+    /// Replays the move sequence cached in `slot` directly against the
+    /// stepper outputs, skipping acceleration planning entirely.
+    ///
+    /// Returns `false` if `slot` is out of range, nothing has been recorded
+    /// into it yet, or the planner's current position no longer matches
+    /// where the recording began - either way the caller should fall back
+    /// to planning the moves normally instead.
+    fn replay_recorded(&self, slot: usize) -> bool {
+        let handle = match self.replays.borrow().get(slot).copied().flatten() {
+            Some(handle) => handle,
+            None => return false,
+        };
+        self.motion.borrow_mut().replay(&handle).is_ok()
+    }
+}
+
+/// One tick of work for a single byte source: drains whatever `transport`
+/// has ready, through `receiver`, into `queue`. Never completes - it's
+/// meant to be polled forever by the executor, yielding `Pending` after
+/// each tick so the other tasks get a turn.
+struct ReaderTask<'p, T: 'p> {
+    transport: &'p RefCell<T>,
+    receiver: &'p RefCell<LineReceiver>,
+    queue: &'p RefCell<GcodeQueue<Line>>,
+}
+
+impl<'p, T: Transport> Future for ReaderTask<'p, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        this.receiver
+            .borrow_mut()
+            .poll(&mut *this.transport.borrow_mut(), &mut *this.queue.borrow_mut());
+        Poll::Pending
+    }
+}
+
+/// Once its next deadline (driven by `HardwareTime`) has elapsed, checks
+/// whether a command is queued and sets the following deadline; it returns
+/// `Pending` on every poll. It does not step the motion planner and does
+/// not pop the queued command, which stays at the head of the queue. The
+/// deadline is checked on every `poll_all` tick rather than awaited until
+/// it fires: `HardwareTime` exposes a free-running tick counter with no
+/// interrupt/waker hook, so there is nothing to register a real wakeup
+/// with, and the check is only as fine as the tick granularity.
+struct MotionTask<'p, 'a: 'p, H: 'a> {
+    queue: &'p RefCell<GcodeQueue<Line>>,
+    motion: &'p RefCell<CartesianMotionPlanner<'a, &'a H>>,
+    hardware: &'p H,
+    next_deadline: Cell<u32>,
+}
+
+impl<'p, 'a, H: HardwareTime> Future for MotionTask<'p, 'a, H> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        if this.hardware.ticks() < this.next_deadline.get() {
+            return Poll::Pending;
+        }
+
+        if this.queue.borrow().peek().is_some() {
+            // Placeholder: this deliberately does not dispatch the queued
+            // command anywhere yet. Real per-axis step dispatch belongs to
+            // the `motion` crate, which this tree doesn't vendor, so there
+            // is nothing here to hand it to - the planner borrow is taken
+            // only so the lock ordering future dispatch code needs is
+            // already in place. `handle_line` already acked this command to
+            // the host, so popping it here with nowhere to send it would
+            // silently destroy work the host believes is queued; peek and
+            // leave it in place until real dispatch exists to pop it.
+            let _planner = this.motion.borrow_mut();
+        }
+        this.next_deadline.set(this.hardware.ticks() + STEP_INTERVAL_TICKS);
+        Poll::Pending
+    }
+}
+
+/// Minimal cooperative single-core async executor. No heap: tasks are
+/// plain stack/static futures and polling never allocates. Each task
+/// returns `Pending` forever, so `poll_all` is meant to be called once per
+/// tick by the caller rather than driven to completion.
+///
+/// This is, by design, a tick-driven poll loop wearing a `Future` API, not
+/// a real scheduler: there is no ready-set and no wake-on-event, so every
+/// task is polled every tick regardless of whether it has work. That is
+/// the accepted scope of this executor, not a placeholder for one -
+/// genuine wake-on-readiness (the reader task waking only when a byte
+/// arrives, the motion task waking only at its deadline) needs the
+/// `Transport`/`HardwareTime` traits from the unvendored `hardware` crate
+/// to expose an interrupt or wake hook, which they don't today. Using
+/// `Future` here buys a consistent task shape to adopt that once it
+/// exists, without another round of signature churn; it does not deliver
+/// precise timing today, and shouldn't be read as though it does.
+struct Executor<'p, 'a: 'p, H: 'a> {
+    serial_reader: ReaderTask<'p, DecompressingTransport<Serial>>,
+    motion: MotionTask<'p, 'a, H>,
+}
+
+impl<'p, 'a, H: HardwareTime> Executor<'p, 'a, H> {
+    fn poll_all(&mut self) {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let _ = Pin::new(&mut self.serial_reader).poll(&mut cx);
+        let _ = Pin::new(&mut self.motion).poll(&mut cx);
+    }
+}
+
+/// A waker that does nothing when woken. Every task here is repolled once
+/// per tick regardless of whether it was woken, so there's no real
+/// interrupt-driven wakeup to hook up yet.
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        raw_waker()
+    }
+    fn no_op(_: *const ()) {}
+    fn raw_waker() -> RawWaker {
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    unsafe { Waker::from_raw(raw_waker()) }
+}
+
+/// Single-producer/single-consumer ring buffer of queued items.
+///
+/// The serial/network receivers are the sole producers and the motion task
+/// polled from `Printer::update` is the sole reader (it peeks at the head
+/// and does not pop it), so head/tail can each be owned by their side
+/// without locking. A stored `len` disambiguates full from empty instead of
+/// reserving a slot, since `Line` isn't `Copy` and we'd rather keep the
+/// full 32-slot capacity. Generic over the stored item so its head/tail/len
+/// bookkeeping can be exercised in tests without needing a real `Line`.
+struct GcodeQueue<T> {
+    buffer: [Option<T>; 32],
+    head: usize,
+    tail: usize,
+    len: usize,
+}
+
+/// Returned by `GcodeQueue::push` when the queue has no free slots.
+#[derive(Debug)]
+struct QueueFullError;
+
+impl<T> GcodeQueue<T> {
+    const CAPACITY: usize = 32;
+
+    fn new() -> GcodeQueue<T> {
+        GcodeQueue {
+            buffer: [const { None }; 32],
+            head: 0,
+            tail: 0,
+            len: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == Self::CAPACITY
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, line: T) -> Result<(), QueueFullError> {
+        if self.is_full() {
+            return Err(QueueFullError);
+        }
+        self.buffer[self.head] = Some(line);
+        self.head = (self.head + 1) % Self::CAPACITY;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let line = self.buffer[self.tail].take();
+        self.tail = (self.tail + 1) % Self::CAPACITY;
+        self.len -= 1;
+        line
+    }
+
+    /// Undoes the most recent `push`, returning the queue to the state it
+    /// was in before that call. Used to roll back commands that were
+    /// queued on behalf of a line that didn't fully fit, so a resend of
+    /// that line doesn't double-queue the commands that did fit the first
+    /// time around.
+    fn unpush(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.head = (self.head + Self::CAPACITY - 1) % Self::CAPACITY;
+        self.len -= 1;
+        self.buffer[self.head].take()
+    }
+
+    fn peek(&self) -> Option<&T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.buffer[self.tail].as_ref()
+    }
+}
+
+/// Streaming gzip/DEFLATE decompression, so a host can send a compressed
+/// G-code stream over a transport that otherwise only carries plain text.
+/// Everything here is driven one input byte at a time with a fixed 32 KiB
+/// window and no heap, since a whole compressed job can be far larger than
+/// anything that fits in memory at once on this target.
+mod gzip {
+    use core::mem;
+
+    const MAXBITS: usize = 15;
+    const MAXLCODES: usize = 288;
+    const MAXDCODES: usize = 30;
+    const MAXCODES: usize = MAXLCODES + MAXDCODES;
+    const WINDOW_SIZE: usize = 32 * 1024;
+
+    const LENGTH_BASE: [u16; 29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+        131, 163, 195, 227, 258,
+    ];
+    const LENGTH_EXTRA: [u8; 29] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+    ];
+    const DIST_BASE: [u16; 30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+        2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+    ];
+    const DIST_EXTRA: [u8; 30] = [
+        0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12,
+        13, 13,
+    ];
+    const CLEN_ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+
+    /// Canonical Huffman decode table built from a list of per-symbol code
+    /// lengths, following the "puff.c" technique (RFC 1951 3.2.2).
+    #[derive(Clone, Copy)]
+    struct Huffman {
+        counts: [u16; MAXBITS + 1],
+        symbols: [u16; MAXCODES],
+    }
+
+    impl Huffman {
+        fn new() -> Huffman {
+            Huffman {
+                counts: [0; MAXBITS + 1],
+                symbols: [0; MAXCODES],
+            }
+        }
+
+        fn construct(lengths: &[u8]) -> Huffman {
+            let mut h = Huffman::new();
+            for &len in lengths {
+                h.counts[len as usize] += 1;
+            }
+            h.counts[0] = 0;
+
+            let mut offsets = [0u16; MAXBITS + 2];
+            for len in 1..=MAXBITS {
+                offsets[len + 1] = offsets[len] + h.counts[len];
+            }
+            for (symbol, &len) in lengths.iter().enumerate() {
+                if len != 0 {
+                    h.symbols[offsets[len as usize] as usize] = symbol as u16;
+                    offsets[len as usize] += 1;
+                }
+            }
+            h
+        }
+    }
+
+    /// Bit-at-a-time reader over bytes fed in one at a time. Every read is
+    /// atomic - it either fully succeeds or leaves the buffer untouched - so
+    /// a caller can always retry once more input arrives without losing
+    /// bits already consumed.
+    struct BitReader {
+        buf: u32,
+        nbits: u32,
+    }
+
+    impl BitReader {
+        fn new() -> BitReader {
+            BitReader { buf: 0, nbits: 0 }
+        }
+
+        fn feed(&mut self, byte: u8) {
+            self.buf |= (byte as u32) << self.nbits;
+            self.nbits += 8;
+        }
+
+        fn get_bit(&mut self) -> Option<u32> {
+            if self.nbits == 0 {
+                return None;
+            }
+            let bit = self.buf & 1;
+            self.buf >>= 1;
+            self.nbits -= 1;
+            Some(bit)
+        }
+
+        fn get_bits(&mut self, n: u32) -> Option<u32> {
+            if self.nbits < n {
+                return None;
+            }
+            let value = self.buf & ((1u32 << n) - 1);
+            self.buf >>= n;
+            self.nbits -= n;
+            Some(value)
+        }
+
+        /// Drops any partially-consumed bits so the next read starts at a
+        /// byte boundary (used before a stored block's length fields).
+        fn align_to_byte(&mut self) {
+            let drop = self.nbits % 8;
+            self.buf >>= drop;
+            self.nbits -= drop;
+        }
+    }
+
+    /// Resumable in-progress canonical-Huffman symbol decode, so a partial
+    /// decode can be abandoned (on `NeedInput`) and retried once more input
+    /// bits are available without losing the bits already consumed.
+    #[derive(Clone, Copy)]
+    struct PartialDecode {
+        code: i32,
+        first: i32,
+        index: i32,
+        len: usize,
+    }
+
+    impl PartialDecode {
+        fn new() -> PartialDecode {
+            PartialDecode {
+                code: 0,
+                first: 0,
+                index: 0,
+                len: 1,
+            }
+        }
+
+        fn step(&mut self, huff: &Huffman, br: &mut BitReader) -> Option<Result<u16, ()>> {
+            while self.len <= MAXBITS {
+                let bit = br.get_bit()?;
+                self.code |= bit as i32;
+                let count = huff.counts[self.len] as i32;
+                if self.code - self.first < count {
+                    return Some(Ok(
+                        huff.symbols[(self.index + (self.code - self.first)) as usize]
+                    ));
+                }
+                self.index += count;
+                self.first += count;
+                self.first <<= 1;
+                self.code <<= 1;
+                self.len += 1;
+            }
+            Some(Err(()))
+        }
+    }
+
+    enum BlockState {
+        /// Expect a 3-bit block header (1 bit BFINAL, 2 bits BTYPE).
+        BlockHeader,
+        StoredLenBytes { collected: [u8; 4], have: usize },
+        StoredCopy { remaining: u16 },
+        DynamicHeader,
+        DynamicClenLengths {
+            hlit: usize,
+            hdist: usize,
+            hclen: usize,
+            lengths: [u8; 19],
+            have: usize,
+        },
+        DynamicTreeLengths {
+            hlit: usize,
+            hdist: usize,
+            clen_tree: Huffman,
+            lengths: [u8; MAXCODES],
+            have: usize,
+            partial: PartialDecode,
+        },
+        /// A repeat-code symbol (16/17/18) has already been decoded; only
+        /// its extra bits remain, kept as a distinct state so a
+        /// `NeedInput` here can't be confused with "decode the next
+        /// code-length symbol" and accidentally reinterpret the extra
+        /// bits as a fresh Huffman code.
+        DynamicTreeRepeat {
+            hlit: usize,
+            hdist: usize,
+            clen_tree: Huffman,
+            lengths: [u8; MAXCODES],
+            have: usize,
+            symbol: u16,
+        },
+        Decode {
+            litlen: Huffman,
+            dist: Huffman,
+            partial: PartialDecode,
+        },
+        DecodeLenExtra {
+            litlen: Huffman,
+            dist: Huffman,
+            length_symbol: usize,
+        },
+        DecodeDistSymbol {
+            litlen: Huffman,
+            dist: Huffman,
+            length: u16,
+            partial: PartialDecode,
+        },
+        DecodeDistExtra {
+            litlen: Huffman,
+            dist: Huffman,
+            length: u16,
+            dist_symbol: usize,
+        },
+        Match {
+            litlen: Huffman,
+            dist: Huffman,
+            length: u16,
+            distance: u16,
+        },
+        StreamDone,
+        Error,
+    }
+
+    enum Step {
+        NeedInput,
+        Output(u8),
+        BlockBoundary,
+        Done,
+        Err,
+    }
+
+    fn fixed_trees() -> (Huffman, Huffman) {
+        let mut lengths = [0u8; MAXLCODES];
+        for i in 0..144 {
+            lengths[i] = 8;
+        }
+        for i in 144..256 {
+            lengths[i] = 9;
+        }
+        for i in 256..280 {
+            lengths[i] = 7;
+        }
+        for i in 280..288 {
+            lengths[i] = 8;
+        }
+        let litlen = Huffman::construct(&lengths);
+        let dist_lengths = [5u8; MAXDCODES];
+        let dist = Huffman::construct(&dist_lengths);
+        (litlen, dist)
+    }
+
+    /// A single DEFLATE (RFC 1951) stream, decoded incrementally from bytes
+    /// fed in one at a time.
+    struct Inflate {
+        br: BitReader,
+        state: BlockState,
+        final_block: bool,
+        window: [u8; WINDOW_SIZE],
+        window_pos: usize,
+        window_filled: usize,
+    }
+
+    impl Inflate {
+        fn new() -> Inflate {
+            Inflate {
+                br: BitReader::new(),
+                state: BlockState::BlockHeader,
+                final_block: false,
+                window: [0; WINDOW_SIZE],
+                window_pos: 0,
+                window_filled: 0,
+            }
+        }
+
+        fn window_push(&mut self, byte: u8) {
+            self.window[self.window_pos] = byte;
+            self.window_pos = (self.window_pos + 1) % WINDOW_SIZE;
+            if self.window_filled < WINDOW_SIZE {
+                self.window_filled += 1;
+            }
+        }
+
+        fn feed(&mut self, byte: u8) {
+            self.br.feed(byte);
+        }
+
+        /// Builds the literal/length and distance trees once all
+        /// `hlit + hdist` code-length entries have been decoded, then
+        /// enters `Decode`.
+        fn finish_dynamic_trees(&mut self, hlit: usize, hdist: usize, lengths: &[u8; MAXCODES]) -> Step {
+            let litlen = Huffman::construct(&lengths[0..hlit]);
+            let dist = Huffman::construct(&lengths[hlit..hlit + hdist]);
+            self.state = BlockState::Decode {
+                litlen,
+                dist,
+                partial: PartialDecode::new(),
+            };
+            Step::BlockBoundary
+        }
+
+        /// Advances the decoder by whatever bits are currently available,
+        /// returning `NeedInput` the moment it runs out rather than
+        /// blocking, so the caller can feed another byte and call again.
+        fn step(&mut self) -> Step {
+            match mem::replace(&mut self.state, BlockState::Error) {
+                BlockState::BlockHeader => {
+                    if self.final_block {
+                        self.state = BlockState::StreamDone;
+                        return Step::Done;
+                    }
+                    // Read BFINAL and BTYPE together (3 bits) so a run
+                    // that's short on input doesn't partially consume the
+                    // header.
+                    let header = match self.br.get_bits(3) {
+                        Some(v) => v,
+                        None => {
+                            self.state = BlockState::BlockHeader;
+                            return Step::NeedInput;
+                        }
+                    };
+                    let bfinal = header & 1;
+                    let btype = header >> 1;
+                    self.final_block = bfinal == 1;
+                    match btype {
+                        0 => {
+                            self.br.align_to_byte();
+                            self.state = BlockState::StoredLenBytes {
+                                collected: [0; 4],
+                                have: 0,
+                            };
+                        }
+                        1 => {
+                            let (litlen, dist) = fixed_trees();
+                            self.state = BlockState::Decode {
+                                litlen,
+                                dist,
+                                partial: PartialDecode::new(),
+                            };
+                        }
+                        2 => {
+                            self.state = BlockState::DynamicHeader;
+                        }
+                        _ => return Step::Err,
+                    }
+                    Step::BlockBoundary
+                }
+                BlockState::StoredLenBytes { mut collected, mut have } => {
+                    while have < 4 {
+                        match self.br.get_bits(8) {
+                            Some(b) => {
+                                collected[have] = b as u8;
+                                have += 1;
+                            }
+                            None => {
+                                self.state = BlockState::StoredLenBytes { collected, have };
+                                return Step::NeedInput;
+                            }
+                        }
+                    }
+                    let len = u16::from_le_bytes([collected[0], collected[1]]);
+                    let nlen = u16::from_le_bytes([collected[2], collected[3]]);
+                    if len != !nlen {
+                        return Step::Err;
+                    }
+                    self.state = BlockState::StoredCopy { remaining: len };
+                    Step::BlockBoundary
+                }
+                BlockState::StoredCopy { remaining } => {
+                    if remaining == 0 {
+                        self.state = BlockState::BlockHeader;
+                        return Step::BlockBoundary;
+                    }
+                    match self.br.get_bits(8) {
+                        Some(b) => {
+                            let byte = b as u8;
+                            self.window_push(byte);
+                            self.state = BlockState::StoredCopy {
+                                remaining: remaining - 1,
+                            };
+                            Step::Output(byte)
+                        }
+                        None => {
+                            self.state = BlockState::StoredCopy { remaining };
+                            Step::NeedInput
+                        }
+                    }
+                }
+                BlockState::DynamicHeader => {
+                    let header = match self.br.get_bits(14) {
+                        Some(v) => v,
+                        None => {
+                            self.state = BlockState::DynamicHeader;
+                            return Step::NeedInput;
+                        }
+                    };
+                    let hlit = (header & 0x1f) as usize + 257;
+                    let hdist = ((header >> 5) & 0x1f) as usize + 1;
+                    let hclen = ((header >> 10) & 0xf) as usize + 4;
+
+                    // hlit tops out at 286 and hdist at 30 in a valid
+                    // stream; a corrupt one can claim up to 288/32, which
+                    // would overrun LENGTH_BASE/DIST_BASE/DIST_EXTRA and the
+                    // lengths buffer (sized for MAXCODES = 288 + 30) further
+                    // down the pipeline. Reject it here instead of letting
+                    // those panic.
+                    if hlit > MAXLCODES - 2 || hdist > MAXDCODES {
+                        self.state = BlockState::Error;
+                        return Step::Err;
+                    }
+
+                    self.state = BlockState::DynamicClenLengths {
+                        hlit,
+                        hdist,
+                        hclen,
+                        lengths: [0; 19],
+                        have: 0,
+                    };
+                    Step::BlockBoundary
+                }
+                BlockState::DynamicClenLengths {
+                    hlit,
+                    hdist,
+                    hclen,
+                    mut lengths,
+                    mut have,
+                } => {
+                    while have < hclen {
+                        match self.br.get_bits(3) {
+                            Some(v) => {
+                                lengths[CLEN_ORDER[have]] = v as u8;
+                                have += 1;
+                            }
+                            None => {
+                                self.state = BlockState::DynamicClenLengths {
+                                    hlit,
+                                    hdist,
+                                    hclen,
+                                    lengths,
+                                    have,
+                                };
+                                return Step::NeedInput;
+                            }
+                        }
+                    }
+                    let clen_tree = Huffman::construct(&lengths);
+                    self.state = BlockState::DynamicTreeLengths {
+                        hlit,
+                        hdist,
+                        clen_tree,
+                        lengths: [0; MAXCODES],
+                        have: 0,
+                        partial: PartialDecode::new(),
+                    };
+                    Step::BlockBoundary
+                }
+                BlockState::DynamicTreeLengths {
+                    hlit,
+                    hdist,
+                    clen_tree,
+                    lengths,
+                    have,
+                    mut partial,
+                } => {
+                    let total = hlit + hdist;
+                    if have >= total {
+                        return self.finish_dynamic_trees(hlit, hdist, &lengths);
+                    }
+                    let symbol = match partial.step(&clen_tree, &mut self.br) {
+                        Some(Ok(s)) => s,
+                        Some(Err(())) => return Step::Err,
+                        None => {
+                            self.state = BlockState::DynamicTreeLengths {
+                                hlit,
+                                hdist,
+                                clen_tree,
+                                lengths,
+                                have,
+                                partial,
+                            };
+                            return Step::NeedInput;
+                        }
+                    };
+                    match symbol {
+                        0..=15 => {
+                            let mut lengths = lengths;
+                            lengths[have] = symbol as u8;
+                            self.state = BlockState::DynamicTreeLengths {
+                                hlit,
+                                hdist,
+                                clen_tree,
+                                lengths,
+                                have: have + 1,
+                                partial: PartialDecode::new(),
+                            };
+                        }
+                        16..=18 => {
+                            if symbol == 16 && have == 0 {
+                                return Step::Err;
+                            }
+                            self.state = BlockState::DynamicTreeRepeat {
+                                hlit,
+                                hdist,
+                                clen_tree,
+                                lengths,
+                                have,
+                                symbol,
+                            };
+                        }
+                        _ => return Step::Err,
+                    }
+                    Step::BlockBoundary
+                }
+                BlockState::DynamicTreeRepeat {
+                    hlit,
+                    hdist,
+                    clen_tree,
+                    mut lengths,
+                    mut have,
+                    symbol,
+                } => {
+                    let total = hlit + hdist;
+                    let (extra_bits, run_base, fill) = match symbol {
+                        16 => (2, 3, lengths[have - 1]),
+                        17 => (3, 3, 0),
+                        18 => (7, 11, 0),
+                        _ => unreachable!(),
+                    };
+                    let extra = match self.br.get_bits(extra_bits) {
+                        Some(v) => v,
+                        None => {
+                            self.state = BlockState::DynamicTreeRepeat {
+                                hlit,
+                                hdist,
+                                clen_tree,
+                                lengths,
+                                have,
+                                symbol,
+                            };
+                            return Step::NeedInput;
+                        }
+                    };
+                    for _ in 0..(run_base + extra) {
+                        if have >= total {
+                            break;
+                        }
+                        lengths[have] = fill;
+                        have += 1;
+                    }
+                    self.state = BlockState::DynamicTreeLengths {
+                        hlit,
+                        hdist,
+                        clen_tree,
+                        lengths,
+                        have,
+                        partial: PartialDecode::new(),
+                    };
+                    Step::BlockBoundary
+                }
+                BlockState::Decode {
+                    litlen,
+                    dist,
+                    mut partial,
+                } => {
+                    let symbol = match partial.step(&litlen, &mut self.br) {
+                        Some(Ok(s)) => s,
+                        Some(Err(())) => return Step::Err,
+                        None => {
+                            self.state = BlockState::Decode { litlen, dist, partial };
+                            return Step::NeedInput;
+                        }
+                    };
+                    if symbol < 256 {
+                        let byte = symbol as u8;
+                        self.window_push(byte);
+                        self.state = BlockState::Decode {
+                            litlen,
+                            dist,
+                            partial: PartialDecode::new(),
+                        };
+                        Step::Output(byte)
+                    } else if symbol == 256 {
+                        self.state = BlockState::BlockHeader;
+                        Step::BlockBoundary
+                    } else {
+                        self.state = BlockState::DecodeLenExtra {
+                            litlen,
+                            dist,
+                            length_symbol: (symbol - 257) as usize,
+                        };
+                        Step::BlockBoundary
+                    }
+                }
+                BlockState::DecodeLenExtra {
+                    litlen,
+                    dist,
+                    length_symbol,
+                } => {
+                    let extra_bits = LENGTH_EXTRA[length_symbol] as u32;
+                    let extra = if extra_bits == 0 {
+                        0
+                    } else {
+                        match self.br.get_bits(extra_bits) {
+                            Some(v) => v,
+                            None => {
+                                self.state = BlockState::DecodeLenExtra {
+                                    litlen,
+                                    dist,
+                                    length_symbol,
+                                };
+                                return Step::NeedInput;
+                            }
+                        }
+                    };
+                    let length = LENGTH_BASE[length_symbol] + extra as u16;
+                    self.state = BlockState::DecodeDistSymbol {
+                        litlen,
+                        dist,
+                        length,
+                        partial: PartialDecode::new(),
+                    };
+                    Step::BlockBoundary
+                }
+                BlockState::DecodeDistSymbol {
+                    litlen,
+                    dist,
+                    length,
+                    mut partial,
+                } => {
+                    let symbol = match partial.step(&dist, &mut self.br) {
+                        Some(Ok(s)) => s,
+                        Some(Err(())) => return Step::Err,
+                        None => {
+                            self.state = BlockState::DecodeDistSymbol {
+                                litlen,
+                                dist,
+                                length,
+                                partial,
+                            };
+                            return Step::NeedInput;
+                        }
+                    };
+                    self.state = BlockState::DecodeDistExtra {
+                        litlen,
+                        dist,
+                        length,
+                        dist_symbol: symbol as usize,
+                    };
+                    Step::BlockBoundary
+                }
+                BlockState::DecodeDistExtra {
+                    litlen,
+                    dist,
+                    length,
+                    dist_symbol,
+                } => {
+                    let extra_bits = DIST_EXTRA[dist_symbol] as u32;
+                    let extra = if extra_bits == 0 {
+                        0
+                    } else {
+                        match self.br.get_bits(extra_bits) {
+                            Some(v) => v,
+                            None => {
+                                self.state = BlockState::DecodeDistExtra {
+                                    litlen,
+                                    dist,
+                                    length,
+                                    dist_symbol,
+                                };
+                                return Step::NeedInput;
+                            }
+                        }
+                    };
+                    let distance = DIST_BASE[dist_symbol] + extra as u16;
+                    self.state = BlockState::Match {
+                        litlen,
+                        dist,
+                        length,
+                        distance,
+                    };
+                    Step::BlockBoundary
+                }
+                BlockState::Match {
+                    litlen,
+                    dist,
+                    length,
+                    distance,
+                } => {
+                    if length == 0 {
+                        self.state = BlockState::Decode {
+                            litlen,
+                            dist,
+                            partial: PartialDecode::new(),
+                        };
+                        return Step::BlockBoundary;
+                    }
+                    let src = (self.window_pos + WINDOW_SIZE - distance as usize) % WINDOW_SIZE;
+                    let byte = self.window[src];
+                    self.window_push(byte);
+                    self.state = BlockState::Match {
+                        litlen,
+                        dist,
+                        length: length - 1,
+                        distance,
+                    };
+                    Step::Output(byte)
+                }
+                BlockState::StreamDone => {
+                    self.state = BlockState::StreamDone;
+                    Step::Done
+                }
+                BlockState::Error => Step::Err,
+            }
+        }
+    }
+
+    enum GzipState {
+        Magic1,
+        Magic2,
+        Method,
+        Flags,
+        Mtime(u8),
+        Xfl,
+        Os,
+        ExtraLen1,
+        ExtraLen2(u8),
+        ExtraData(u16),
+        Name,
+        Comment,
+        Hcrc(u8),
+        Body,
+        Crc32(u8, u32),
+        Isize(u8, u32),
+        Done,
+    }
+
+    /// Result of one `GzipReader::poll` call.
+    pub(crate) enum GzipPoll {
+        /// No output is ready; feed one more byte before polling again.
+        NeedInput,
+        /// One decompressed byte, ready to hand to the caller.
+        Output(u8),
+        /// The stream's CRC32 and ISIZE trailer have been validated; there
+        /// is nothing left to produce.
+        Done,
+    }
+
+    const CRC_TABLE_SIZE: usize = 256;
+
+    fn make_crc_table() -> [u32; CRC_TABLE_SIZE] {
+        let mut table = [0u32; CRC_TABLE_SIZE];
+        for n in 0..CRC_TABLE_SIZE {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                if c & 1 != 0 {
+                    c = 0xedb88320 ^ (c >> 1);
+                } else {
+                    c >>= 1;
+                }
+            }
+            table[n] = c;
+        }
+        table
+    }
+
+    /// Validates and strips a gzip (RFC 1952) container around a DEFLATE
+    /// stream, decoding it incrementally one input byte at a time and
+    /// rejecting the stream if the trailing CRC32 or ISIZE don't match.
+    pub(crate) struct GzipReader {
+        state: GzipState,
+        flags: u8,
+        extra_remaining: u16,
+        inflate: Inflate,
+        crc_table: [u32; CRC_TABLE_SIZE],
+        crc: u32,
+        size: u32,
+    }
+
+    impl GzipReader {
+        pub(crate) fn new() -> GzipReader {
+            GzipReader {
+                state: GzipState::Magic1,
+                flags: 0,
+                extra_remaining: 0,
+                inflate: Inflate::new(),
+                crc_table: make_crc_table(),
+                crc: 0xffffffff,
+                size: 0,
+            }
+        }
+
+        fn crc_update(&mut self, byte: u8) {
+            let idx = ((self.crc ^ byte as u32) & 0xff) as usize;
+            self.crc = self.crc_table[idx] ^ (self.crc >> 8);
+        }
+
+        /// Pulls at most one decompressed byte out of whatever input has
+        /// already been `feed`-ed. A single fed byte can resolve a DEFLATE
+        /// match of up to 258 bytes, so callers must keep calling `poll`
+        /// - each time it reports `NeedInput`, feed exactly one more byte
+        /// and poll again - rather than assuming one output per input.
+        pub(crate) fn poll(&mut self) -> Result<GzipPoll, ()> {
+            if let GzipState::Done = self.state {
+                return Ok(GzipPoll::Done);
+            }
+            if let GzipState::Body = self.state {
+                loop {
+                    match self.inflate.step() {
+                        Step::Output(b) => {
+                            self.crc_update(b);
+                            self.size = self.size.wrapping_add(1);
+                            return Ok(GzipPoll::Output(b));
+                        }
+                        Step::BlockBoundary => continue,
+                        Step::NeedInput => return Ok(GzipPoll::NeedInput),
+                        Step::Done => {
+                            self.state = GzipState::Crc32(0, 0);
+                            return Ok(GzipPoll::NeedInput);
+                        }
+                        Step::Err => return Err(()),
+                    }
+                }
+            }
+            Ok(GzipPoll::NeedInput)
+        }
+
+        /// Feeds one compressed input byte. Header and trailer bytes are
+        /// consumed directly; body bytes are only handed to the inflater's
+        /// bit reader, without stepping it - call `poll` to pull decoded
+        /// output back out, one byte at a time, before feeding the next
+        /// input byte.
+        pub(crate) fn feed(&mut self, byte: u8) -> Result<(), ()> {
+            match self.state {
+                GzipState::Magic1 => {
+                    if byte != 0x1f {
+                        return Err(());
+                    }
+                    self.state = GzipState::Magic2;
+                    Ok(())
+                }
+                GzipState::Magic2 => {
+                    if byte != 0x8b {
+                        return Err(());
+                    }
+                    self.state = GzipState::Method;
+                    Ok(())
+                }
+                GzipState::Method => {
+                    if byte != 8 {
+                        return Err(());
+                    }
+                    self.state = GzipState::Flags;
+                    Ok(())
+                }
+                GzipState::Flags => {
+                    self.flags = byte;
+                    self.state = GzipState::Mtime(0);
+                    Ok(())
+                }
+                GzipState::Mtime(n) => {
+                    self.state = if n + 1 == 4 { GzipState::Xfl } else { GzipState::Mtime(n + 1) };
+                    Ok(())
+                }
+                GzipState::Xfl => {
+                    self.state = GzipState::Os;
+                    Ok(())
+                }
+                GzipState::Os => {
+                    self.state = if self.flags & 0x04 != 0 {
+                        GzipState::ExtraLen1
+                    } else {
+                        self.advance_past_os()
+                    };
+                    Ok(())
+                }
+                GzipState::ExtraLen1 => {
+                    self.extra_remaining = byte as u16;
+                    self.state = GzipState::ExtraLen2(byte);
+                    Ok(())
+                }
+                GzipState::ExtraLen2(lo) => {
+                    self.extra_remaining |= (byte as u16) << 8;
+                    let _ = lo;
+                    self.state = if self.extra_remaining == 0 {
+                        self.advance_past_extra()
+                    } else {
+                        GzipState::ExtraData(self.extra_remaining)
+                    };
+                    Ok(())
+                }
+                GzipState::ExtraData(remaining) => {
+                    self.state = if remaining <= 1 {
+                        self.advance_past_extra()
+                    } else {
+                        GzipState::ExtraData(remaining - 1)
+                    };
+                    Ok(())
+                }
+                GzipState::Name => {
+                    if byte == 0 {
+                        self.state = self.advance_past_name();
+                    }
+                    Ok(())
+                }
+                GzipState::Comment => {
+                    if byte == 0 {
+                        self.state = self.advance_past_comment();
+                    }
+                    Ok(())
+                }
+                GzipState::Hcrc(n) => {
+                    self.state = if n + 1 == 2 { GzipState::Body } else { GzipState::Hcrc(n + 1) };
+                    Ok(())
+                }
+                GzipState::Body => {
+                    self.inflate.feed(byte);
+                    Ok(())
+                }
+                GzipState::Crc32(n, acc) => {
+                    let acc = acc | ((byte as u32) << (8 * n));
+                    self.state = if n + 1 == 4 {
+                        if acc != (self.crc ^ 0xffffffff) {
+                            return Err(());
+                        }
+                        GzipState::Isize(0, 0)
+                    } else {
+                        GzipState::Crc32(n + 1, acc)
+                    };
+                    Ok(())
+                }
+                GzipState::Isize(n, acc) => {
+                    let acc = acc | ((byte as u32) << (8 * n));
+                    self.state = if n + 1 == 4 {
+                        if acc != self.size {
+                            return Err(());
+                        }
+                        GzipState::Done
+                    } else {
+                        GzipState::Isize(n + 1, acc)
+                    };
+                    Ok(())
+                }
+                GzipState::Done => Err(()),
+            }
+        }
+
+        fn advance_past_os(&mut self) -> GzipState {
+            if self.flags & 0x08 != 0 {
+                GzipState::Name
+            } else {
+                self.advance_past_name()
+            }
+        }
+        fn advance_past_extra(&mut self) -> GzipState {
+            if self.flags & 0x08 != 0 {
+                GzipState::Name
+            } else {
+                self.advance_past_name()
+            }
+        }
+        fn advance_past_name(&mut self) -> GzipState {
+            if self.flags & 0x10 != 0 {
+                GzipState::Comment
+            } else {
+                self.advance_past_comment()
+            }
+        }
+        fn advance_past_comment(&mut self) -> GzipState {
+            if self.flags & 0x02 != 0 {
+                GzipState::Hcrc(0)
+            } else {
+                GzipState::Body
+            }
+        }
+    }
+}
+
+/// First two bytes of a gzip stream (RFC 1952); used to detect a
+/// compressed connection before any framing has been read.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Which mode a `DecompressingTransport` has settled into for its
+/// connection: still sniffing the first couple of bytes, decoding gzip, or
+/// passing bytes through unchanged.
+enum DecompressMode {
+    Detecting,
+    Gzip,
+    PlainText,
+}
+
+/// Gzip-detection/inflation state for a `DecompressingTransport`, split out
+/// from the transport it wraps so the network path can keep this state
+/// alive across ticks even though the `NetTransport` it decodes through is
+/// rebuilt fresh every tick (see `Printer::net_decompress`).
+struct DecompressState {
+    mode: DecompressMode,
+    stash: [u8; 2],
+    stash_len: u8,
+
+    /// Once detection has settled on `PlainText`, the index of the next
+    /// stashed byte still owed to the caller - at most the 2 peeked during
+    /// detection, so this never needs to hold a decompressed burst.
+    stash_pos: u8,
+    gzip: gzip::GzipReader,
+}
+
+impl DecompressState {
+    fn new() -> DecompressState {
+        DecompressState {
+            mode: DecompressMode::Detecting,
+            stash: [0; 2],
+            stash_len: 0,
+            stash_pos: 0,
+            gzip: gzip::GzipReader::new(),
+        }
+    }
+
+    /// Transparently inflates a gzip-framed byte stream pulled from `inner`
+    /// if the connection opened with the gzip magic bytes, otherwise passes
+    /// bytes through unchanged. The choice is made once, from the first two
+    /// bytes of the connection, so a host that doesn't compress its stream
+    /// pays no cost beyond that initial peek.
+    fn try_read_byte<T: Transport>(&mut self, inner: &mut T) -> Result<u8, ()> {
+        loop {
+            match self.mode {
+                DecompressMode::PlainText => {
+                    if self.stash_pos < self.stash_len {
+                        let byte = self.stash[self.stash_pos as usize];
+                        self.stash_pos += 1;
+                        return Ok(byte);
+                    }
+                    return inner.try_read_byte();
+                }
+                DecompressMode::Detecting => {
+                    let byte = inner.try_read_byte()?;
+                    self.stash[self.stash_len as usize] = byte;
+                    self.stash_len += 1;
+                    if self.stash_len as usize == self.stash.len() {
+                        if self.stash == GZIP_MAGIC {
+                            self.mode = DecompressMode::Gzip;
+                            let _ = self.gzip.feed(self.stash[0]);
+                            let _ = self.gzip.feed(self.stash[1]);
+                        } else {
+                            self.mode = DecompressMode::PlainText;
+                            self.stash_pos = 0;
+                        }
+                    }
+                }
+                // Pulls the inflater forward one step at a time, feeding a
+                // fresh input byte only when it actually needs one, so at
+                // most one decompressed byte is ever produced per call -
+                // no scratch buffer has to be sized for a 258-byte match.
+                DecompressMode::Gzip => match self.gzip.poll() {
+                    Ok(gzip::GzipPoll::Output(byte)) => return Ok(byte),
+                    Ok(gzip::GzipPoll::NeedInput) => {
+                        let byte = inner.try_read_byte()?;
+                        if self.gzip.feed(byte).is_err() {
+                            // A corrupt compressed stream has no good
+                            // recovery short of the host reconnecting; stop
+                            // yielding bytes rather than feeding the parser
+                            // garbage.
+                            return Err(());
+                        }
+                    }
+                    Ok(gzip::GzipPoll::Done) | Err(()) => return Err(()),
+                },
+            }
+        }
+    }
+}
+
+/// Wraps a transport, transparently inflating a gzip-framed G-code stream
+/// if the connection opens with the gzip magic bytes, otherwise passing
+/// bytes through unchanged. Owns its `DecompressState` since `T` (e.g.
+/// `Serial`) is itself held for the printer's whole lifetime; the network
+/// path can't do this and uses `DecompressState` directly instead (see
+/// `Printer::net_decompress`).
+struct DecompressingTransport<T> {
+    inner: T,
+    state: DecompressState,
+}
+
+impl<T: Transport> DecompressingTransport<T> {
+    fn new(inner: T) -> DecompressingTransport<T> {
+        DecompressingTransport {
+            inner,
+            state: DecompressState::new(),
+        }
+    }
+}
+
+impl<T: Transport> Transport for DecompressingTransport<T> {
+    fn try_read_byte(&mut self) -> Result<u8, ()> {
+        self.state.try_read_byte(&mut self.inner)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.inner.write(bytes);
+    }
+}
+
+/// Same gzip-decompression behavior as `DecompressingTransport`, but
+/// borrowing its state instead of owning it. Used for the network command
+/// channel, where the inner transport (`NetTransport`) is rebuilt fresh
+/// every tick around a transient `&mut SocketSet` and so can't be the `T`
+/// in a stored `DecompressingTransport<T>` - the `DecompressState` has to
+/// live in `Printer` instead and be reattached to a new transport each
+/// call.
+#[cfg(feature = "net")]
+struct DecompressingTransportRef<'s, T> {
+    inner: T,
+    state: &'s mut DecompressState,
+}
+
+#[cfg(feature = "net")]
+impl<'s, T: Transport> Transport for DecompressingTransportRef<'s, T> {
+    fn try_read_byte(&mut self) -> Result<u8, ()> {
+        self.state.try_read_byte(&mut self.inner)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.inner.write(bytes);
+    }
+}
+
+/// Outcome of checking a raw line for Marlin-style `Nxxxx ... *cc` framing.
+enum Checked<'a> {
+    /// The line carried a line number and checksum and both matched; holds
+    /// the payload with the framing stripped and the line number checked.
+    Framed(&'a [u8], u32),
+    /// The line carried neither `N` nor `*`; accepted without sequencing.
+    Unframed(&'a [u8]),
+}
+
+/// Validates `line` against the `N<number> ... *<checksum>` framing.
+///
+/// On success returns the payload with the framing stripped. On a checksum
+/// or sequence mismatch returns `Err` carrying the line number the host
+/// should resend from.
+fn strip_checksum(line: &[u8], last_line: u32) -> Result<Checked, u32> {
+    // Hosts that send CRLF-terminated lines leave a trailing `\r` once the
+    // `\n` itself has been stripped as the line ending; fold it off here so
+    // it neither breaks `checksum.parse::<u8>()` nor throws off the XOR.
+    let line = match line.split_last() {
+        Some((&b'\r', rest)) => rest,
+        _ => line,
+    };
+
+    let star = line.iter().rposition(|&b| b == b'*');
+    let body = match star {
+        Some(i) => &line[..i],
+        None => line,
+    };
+
+    if body.first() != Some(&b'N') {
+        return match star {
+            None => Ok(Checked::Unframed(body)),
+            Some(_) => Err(last_line + 1),
+        };
+    }
+
+    let number_end = body.iter().position(|&b| b == b' ').unwrap_or(body.len());
+    let number = core::str::from_utf8(&body[1..number_end])
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok());
+    let checksum = star
+        .and_then(|i| core::str::from_utf8(&line[i + 1..]).ok())
+        .and_then(|s| s.parse::<u8>().ok());
+
+    match (number, checksum) {
+        (Some(number), Some(checksum))
+            if number == last_line + 1 && xor_checksum(body) == checksum =>
+        {
+            Ok(Checked::Framed(&body[number_end..], number))
+        }
+        _ => Err(last_line + 1),
+    }
+}
+
+/// XORs every byte of `body` together, matching the checksum computed over
+/// the line up to (but not including) the `*`.
+fn xor_checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0u8, |acc, &b| acc ^ b)
+}
+
+/// Writes the decimal digits of `value` into `buf`, returning how many
+/// bytes were written. `buf` must be at least 10 bytes (enough for any
+/// `u32`).
+fn write_u32(value: u32, buf: &mut [u8]) -> usize {
+    if value == 0 {
+        buf[0] = b'0';
+        return 1;
+    }
+
+    let mut tmp = [0u8; 10];
+    let mut len = 0;
+    let mut v = value;
+    while v > 0 {
+        tmp[len] = b'0' + (v % 10) as u8;
+        v /= 10;
+        len += 1;
+    }
+    for i in 0..len {
+        buf[i] = tmp[len - 1 - i];
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xor_checksum_xors_every_byte() {
+        assert_eq!(xor_checksum(b"G1 X10"), b'G' ^ b'1' ^ b' ' ^ b'X' ^ b'1' ^ b'0');
+    }
+
+    #[test]
+    fn strip_checksum_accepts_unframed_lines() {
+        match strip_checksum(b"G28", 0) {
+            Ok(Checked::Unframed(payload)) => assert_eq!(payload, b"G28"),
+            _ => panic!("expected an unframed line"),
+        }
+    }
+
+    #[test]
+    fn strip_checksum_accepts_matching_framed_line() {
+        // xor_checksum(b"N1 G28"): the running XOR after each byte is
+        // 0x7F, 0x5F, 0x18, 0x2A, 0x12, so the checksum is 0x12 == 18
+        match strip_checksum(b"N1 G28*18", 0) {
+            Ok(Checked::Framed(payload, number)) => {
+                assert_eq!(payload, b" G28");
+                assert_eq!(number, 1);
+            }
+            _ => panic!("expected a framed line"),
+        }
+    }
+
+    #[test]
+    fn strip_checksum_trims_trailing_cr_before_parsing() {
+        match strip_checksum(b"N1 G28*18\r", 0) {
+            Ok(Checked::Framed(payload, number)) => {
+                assert_eq!(payload, b" G28");
+                assert_eq!(number, 1);
+            }
+            _ => panic!("expected the trailing CR to be trimmed, not to break parsing"),
+        }
+    }
+
+    #[test]
+    fn strip_checksum_rejects_bad_checksum() {
+        match strip_checksum(b"N1 G28*99", 0) {
+            Err(expected) => assert_eq!(expected, 1),
+            Ok(_) => panic!("expected a checksum mismatch"),
+        }
+    }
+
+    #[test]
+    fn strip_checksum_rejects_out_of_sequence_line() {
+        // last_line is 5, so the next expected number is 6; a line claiming
+        // to be N1 must be rejected with a resend request for 6.
+        match strip_checksum(b"N1 G28*18", 5) {
+            Err(expected) => assert_eq!(expected, 6),
+            Ok(_) => panic!("expected a sequence mismatch"),
+        }
+    }
+
+    #[test]
+    fn gcode_queue_tracks_full_and_empty() {
+        let mut queue: GcodeQueue<u32> = GcodeQueue::new();
+        assert!(queue.is_empty());
+        assert!(!queue.is_full());
+        assert_eq!(queue.pop(), None);
+
+        for i in 0..GcodeQueue::<u32>::CAPACITY {
+            queue.push(i as u32).expect("queue should accept up to CAPACITY items");
+        }
+        assert!(queue.is_full());
+        assert!(queue.push(999).is_err());
+    }
+
+    #[test]
+    fn gcode_queue_wraps_around_after_draining_and_refilling() {
+        let mut queue: GcodeQueue<u32> = GcodeQueue::new();
+        for i in 0..GcodeQueue::<u32>::CAPACITY {
+            queue.push(i as u32).unwrap();
+        }
+
+        // Drain part of the ring, then push the same number of items back
+        // in - this walks head/tail all the way past the end of `buffer`
+        // and back to the start, exercising the wraparound modulo.
+        for i in 0..16 {
+            assert_eq!(queue.pop(), Some(i as u32));
+        }
+        for i in 100..116 {
+            queue.push(i as u32).unwrap();
+        }
+        assert!(queue.is_full());
+
+        for i in 16..32 {
+            assert_eq!(queue.pop(), Some(i as u32));
+        }
+        for i in 100..116 {
+            assert_eq!(queue.pop(), Some(i as u32));
+        }
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn gcode_queue_unpush_rolls_back_the_most_recent_push() {
+        let mut queue: GcodeQueue<u32> = GcodeQueue::new();
+        queue.push(1).unwrap();
+        queue.push(2).unwrap();
+        queue.push(3).unwrap();
+
+        // A line that queues commands 4 and 5 but fails partway through
+        // rolls both back with unpush, leaving 1..=3 exactly as they were.
+        queue.push(4).unwrap();
+        queue.push(5).unwrap();
+        assert_eq!(queue.unpush(), Some(5));
+        assert_eq!(queue.unpush(), Some(4));
+
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert!(queue.is_empty());
+        assert_eq!(queue.unpush(), None);
+    }
+
+    #[test]
+    fn gzip_reader_round_trips_a_match_past_the_old_64_byte_cap() {
+        // gzip of b"G1 X10 Y20 Z5 F1500\n" + b"A" * 300 + b"\nG1 X0 Y0\n".
+        // The 300-byte run of `A`s forces DEFLATE back-references beyond
+        // the 64-byte scratch buffer that used to silently truncate long
+        // matches (see the chunk0-5 fix).
+        const COMPRESSED: [u8; 53] = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x73, 0x37,
+            0x54, 0x88, 0x30, 0x34, 0x50, 0x88, 0x34, 0x32, 0x50, 0x88, 0x32, 0x55,
+            0x70, 0x33, 0x34, 0x35, 0x30, 0xe0, 0x72, 0x1c, 0x05, 0x44, 0x03, 0x2e,
+            0x77, 0x60, 0x00, 0x02, 0xc3, 0xcf, 0x80, 0x0b, 0x00, 0xe9, 0x0e, 0x0b,
+            0xbf, 0x4a, 0x01, 0x00, 0x00,
+        ];
+
+        let header = b"G1 X10 Y20 Z5 F1500\n";
+        let mut expected = [0u8; 330];
+        expected[..header.len()].copy_from_slice(header);
+        for b in expected[header.len()..header.len() + 300].iter_mut() {
+            *b = b'A';
+        }
+        expected[header.len() + 300..].copy_from_slice(b"\nG1 X0 Y0\n");
+
+        let mut reader = gzip::GzipReader::new();
+        let mut out = [0u8; 330];
+        let mut out_len = 0;
+        let mut idx = 0;
+        loop {
+            match reader.poll().expect("decode error") {
+                gzip::GzipPoll::Output(b) => {
+                    out[out_len] = b;
+                    out_len += 1;
+                }
+                gzip::GzipPoll::NeedInput => {
+                    reader.feed(COMPRESSED[idx]).expect("feed error");
+                    idx += 1;
+                }
+                gzip::GzipPoll::Done => break,
+            }
+        }
+
+        assert_eq!(out_len, expected.len());
+        assert_eq!(&out[..out_len], &expected[..]);
+    }
+
+    #[test]
+    fn gzip_reader_rejects_an_oversized_dynamic_header_instead_of_panicking() {
+        // gzip header followed by a dynamic-block header claiming HLIT=31
+        // (hlit = 257 + 31 = 288), which is out of DEFLATE's valid range
+        // (max 286) and would overrun LENGTH_BASE/DIST_BASE/the lengths
+        // buffer further down the state machine if it weren't rejected
+        // here first.
+        const STREAM: [u8; 13] = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xfd, 0x00, 0x00,
+        ];
+
+        let mut reader = gzip::GzipReader::new();
+        let mut idx = 0;
+        loop {
+            match reader.poll() {
+                Ok(gzip::GzipPoll::Output(_)) => panic!("corrupt stream should not decode any output"),
+                Ok(gzip::GzipPoll::NeedInput) => {
+                    assert!(idx < STREAM.len(), "ran out of input before the header was rejected");
+                    reader.feed(STREAM[idx]).expect("header bytes themselves are well-formed");
+                    idx += 1;
+                }
+                Ok(gzip::GzipPoll::Done) => panic!("corrupt stream should not report Done"),
+                Err(()) => return,
+            }
+        }
+    }
+}